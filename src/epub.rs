@@ -0,0 +1,380 @@
+use std::io::{Cursor, Read, Write};
+
+use anyhow::Result;
+use scraper::{Html, Selector};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{Chapter, FictionMeta};
+
+/// A fixed modification timestamp embedded in the package document.
+///
+/// EPUB3 requires a `dcterms:modified` property, but we have no meaningful
+/// per-fiction value to report and deliberately avoid wall-clock time so the
+/// output is reproducible for a given set of chapters.
+const MODIFIED: &str = "1970-01-01T00:00:00Z";
+
+/// Escape the five XML predefined entities so scraped text can be embedded in
+/// the generated XHTML/OPF documents without producing a malformed container.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Wrap a chapter's paragraphs in a minimal XHTML document.
+fn chapter_document(chapter: &Chapter) -> String {
+    let title = escape(&chapter.title);
+    let body = chapter
+        .paragraphs
+        .iter()
+        .map(|p| format!("    <p>{}</p>", escape(p)))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+  <head>
+    <title>{title}</title>
+  </head>
+  <body>
+    <h1>{title}</h1>
+{body}
+  </body>
+</html>
+"#
+    )
+}
+
+/// The manifest `href` and media-type for a cover image, derived from the
+/// actual bytes (and the source URL as a fallback) rather than assumed to be
+/// JPEG, so the declared type matches what strict readers and `epubcheck` see.
+struct Cover<'a> {
+    href: &'static str,
+    media_type: &'static str,
+    data: &'a [u8],
+}
+
+impl<'a> Cover<'a> {
+    fn new(url: Option<&str>, data: &'a [u8]) -> Self {
+        let (href, media_type) = match cover_kind(url, data) {
+            "image/png" => ("cover.png", "image/png"),
+            "image/gif" => ("cover.gif", "image/gif"),
+            "image/webp" => ("cover.webp", "image/webp"),
+            _ => ("cover.jpg", "image/jpeg"),
+        };
+        Self { href, media_type, data }
+    }
+}
+
+/// Guess an image's media-type, preferring the file's magic bytes and falling
+/// back to the cover URL's extension, defaulting to JPEG when neither is clear.
+fn cover_kind(url: Option<&str>, data: &[u8]) -> &'static str {
+    if data.starts_with(&[0x89, b'P', b'N', b'G']) {
+        return "image/png";
+    }
+    if data.starts_with(b"GIF8") {
+        return "image/gif";
+    }
+    if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return "image/jpeg";
+    }
+
+    let extension = url
+        .and_then(|url| url.rsplit('.').next())
+        .map(|ext| ext.split(['?', '#']).next().unwrap_or(ext).to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+/// Build the OPF package document describing the spine, manifest and metadata.
+fn package_document(meta: &FictionMeta, chapters: &[Chapter], cover: Option<&Cover>) -> String {
+    let identifier = escape(&meta.identifier);
+    let title = escape(&meta.title);
+    let author = escape(&meta.author);
+    let description = escape(&meta.description);
+
+    let mut manifest = String::from(
+        r#"    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+"#,
+    );
+    if let Some(cover) = cover {
+        manifest.push_str(&format!(
+            "    <item id=\"cover\" href=\"{}\" media-type=\"{}\" properties=\"cover-image\"/>\n",
+            cover.href, cover.media_type,
+        ));
+    }
+    let mut spine = String::new();
+    for (index, _) in chapters.iter().enumerate() {
+        manifest.push_str(&format!(
+            "    <item id=\"chapter{index}\" href=\"chapter{index}.xhtml\" media-type=\"application/xhtml+xml\"/>\n"
+        ));
+        spine.push_str(&format!("    <itemref idref=\"chapter{index}\"/>\n"));
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="book-id">{identifier}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:creator>{author}</dc:creator>
+    <dc:language>en</dc:language>
+    <dc:description>{description}</dc:description>
+    <meta property="dcterms:modified">{MODIFIED}</meta>
+  </metadata>
+  <manifest>
+{manifest}  </manifest>
+  <spine toc="ncx">
+{spine}  </spine>
+</package>
+"#
+    )
+}
+
+/// Build the NCX navigation document (read by EPUB2 era readers).
+fn ncx_document(meta: &FictionMeta, chapters: &[Chapter]) -> String {
+    let identifier = escape(&meta.identifier);
+    let title = escape(&meta.title);
+    let mut nav_points = String::new();
+    for (index, chapter) in chapters.iter().enumerate() {
+        let order = index + 1;
+        nav_points.push_str(&format!(
+            r#"    <navPoint id="chapter{index}" playOrder="{order}">
+      <navLabel><text>{chapter_title}</text></navLabel>
+      <content src="chapter{index}.xhtml"/>
+    </navPoint>
+"#,
+            chapter_title = escape(&chapter.title)
+        ));
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <head>
+    <meta name="dtb:uid" content="{identifier}"/>
+  </head>
+  <docTitle><text>{title}</text></docTitle>
+  <navMap>
+{nav_points}  </navMap>
+</ncx>
+"#
+    )
+}
+
+/// Build the EPUB3 navigation document (the `properties="nav"` TOC).
+fn nav_document(meta: &FictionMeta, chapters: &[Chapter]) -> String {
+    let title = escape(&meta.title);
+    let mut items = String::new();
+    for (index, chapter) in chapters.iter().enumerate() {
+        items.push_str(&format!(
+            "      <li><a href=\"chapter{index}.xhtml\">{}</a></li>\n",
+            escape(&chapter.title)
+        ));
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+  <head>
+    <title>{title}</title>
+  </head>
+  <body>
+    <nav epub:type="toc">
+      <h1>Table of Contents</h1>
+      <ol>
+{items}      </ol>
+    </nav>
+  </body>
+</html>
+"#
+    )
+}
+
+/// Assemble a valid EPUB3 container from the scraped metadata and chapters.
+///
+/// The `mimetype` entry is written first and stored uncompressed as required by
+/// the OCF specification; everything else is deflated.
+pub fn build(meta: &FictionMeta, chapters: &[Chapter], cover: Option<&[u8]>) -> Result<Vec<u8>> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    writer.start_file("mimetype", stored)?;
+    writer.write_all(b"application/epub+zip")?;
+
+    writer.start_file("META-INF/container.xml", deflated)?;
+    writer.write_all(
+        br#"<?xml version="1.0" encoding="utf-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#,
+    )?;
+
+    let cover = cover.map(|data| Cover::new(meta.cover_url.as_deref(), data));
+    if let Some(cover) = &cover {
+        writer.start_file(format!("OEBPS/{}", cover.href), deflated)?;
+        writer.write_all(cover.data)?;
+    }
+
+    writer.start_file("OEBPS/content.opf", deflated)?;
+    writer.write_all(package_document(meta, chapters, cover.as_ref()).as_bytes())?;
+
+    writer.start_file("OEBPS/toc.ncx", deflated)?;
+    writer.write_all(ncx_document(meta, chapters).as_bytes())?;
+
+    writer.start_file("OEBPS/nav.xhtml", deflated)?;
+    writer.write_all(nav_document(meta, chapters).as_bytes())?;
+
+    for (index, chapter) in chapters.iter().enumerate() {
+        writer.start_file(format!("OEBPS/chapter{index}.xhtml"), deflated)?;
+        writer.write_all(chapter_document(chapter).as_bytes())?;
+    }
+
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Recover the chapters already stored in an EPUB container, in spine order.
+///
+/// Each `chapterN.xhtml` document is parsed back into its heading and paragraph
+/// text so `--update` can rebuild the book around the chapters it already holds
+/// without re-fetching them.
+pub fn read(bytes: &[u8]) -> Result<Vec<Chapter>> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    let heading = Selector::parse("h1").unwrap();
+    let paragraph = Selector::parse("p").unwrap();
+
+    let mut names = archive
+        .file_names()
+        .filter(|name| name.starts_with("OEBPS/chapter") && name.ends_with(".xhtml"))
+        .map(|name| name.to_owned())
+        .collect::<Vec<_>>();
+    names.sort_by_key(|name| chapter_order(name));
+
+    let mut chapters = Vec::with_capacity(names.len());
+    for name in names {
+        let mut contents = String::new();
+        archive.by_name(&name)?.read_to_string(&mut contents)?;
+        let document = Html::parse_document(&contents);
+
+        let title = document
+            .select(&heading)
+            .next()
+            .map(|ele| ele.text().collect::<String>().trim().to_owned())
+            .unwrap_or_default();
+        let paragraphs = document
+            .select(&paragraph)
+            .map(|ele| ele.text().collect::<String>().trim().to_owned())
+            .filter(|text| !text.is_empty())
+            .collect();
+
+        chapters.push(Chapter {
+            name: title.clone(),
+            title,
+            paragraphs,
+        });
+    }
+
+    Ok(chapters)
+}
+
+/// The numeric index encoded in an `OEBPS/chapterN.xhtml` entry name, used to
+/// restore spine order when the zip's directory lists entries out of sequence.
+fn chapter_order(name: &str) -> usize {
+    name.trim_start_matches("OEBPS/chapter")
+        .trim_end_matches(".xhtml")
+        .parse()
+        .unwrap_or(usize::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> FictionMeta {
+        FictionMeta {
+            identifier: "https://example.test/fiction/1/slug".to_owned(),
+            title: "Ampersands & <angles>".to_owned(),
+            author: "A. Writer".to_owned(),
+            description: "A \"quoted\" synopsis.".to_owned(),
+            cover_url: None,
+            chapters: Vec::new(),
+        }
+    }
+
+    fn chapter(title: &str, paragraphs: &[&str]) -> Chapter {
+        Chapter {
+            name: title.to_owned(),
+            title: title.to_owned(),
+            paragraphs: paragraphs.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn escapes_the_five_predefined_entities() {
+        assert_eq!(escape("a & b < c > d \" e ' f"), "a &amp; b &lt; c &gt; d &quot; e &apos; f");
+    }
+
+    #[test]
+    fn build_then_read_round_trips_chapters() {
+        let chapters = vec![
+            chapter("First & Foremost", &["Hello <world>.", "Second paragraph."]),
+            chapter("Second", &["Lone paragraph."]),
+        ];
+        let bytes = build(&meta(), &chapters, None).unwrap();
+        let recovered = read(&bytes).unwrap();
+
+        assert_eq!(recovered.len(), chapters.len());
+        for (got, want) in recovered.iter().zip(&chapters) {
+            assert_eq!(got.title, want.title);
+            assert_eq!(got.paragraphs, want.paragraphs);
+        }
+    }
+
+    #[test]
+    fn chapter_order_sorts_numerically() {
+        let mut names = vec![
+            "OEBPS/chapter10.xhtml".to_owned(),
+            "OEBPS/chapter2.xhtml".to_owned(),
+            "OEBPS/chapter1.xhtml".to_owned(),
+        ];
+        names.sort_by_key(|name| chapter_order(name));
+        assert_eq!(names[0], "OEBPS/chapter1.xhtml");
+        assert_eq!(names[1], "OEBPS/chapter2.xhtml");
+        assert_eq!(names[2], "OEBPS/chapter10.xhtml");
+    }
+
+    #[test]
+    fn cover_kind_prefers_magic_bytes_then_extension() {
+        assert_eq!(cover_kind(None, &[0x89, b'P', b'N', b'G']), "image/png");
+        assert_eq!(cover_kind(None, b"GIF89a"), "image/gif");
+        assert_eq!(cover_kind(None, b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(cover_kind(None, &[0xFF, 0xD8, 0xFF, 0xE0]), "image/jpeg");
+        assert_eq!(cover_kind(Some("https://x.test/c.png?v=2"), b"garble"), "image/png");
+        assert_eq!(cover_kind(Some("https://x.test/c"), b"garble"), "image/jpeg");
+    }
+}
@@ -1,21 +1,45 @@
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use anyhow::{bail, Result};
-use async_compression::tokio::write::BrotliEncoder;
-use clap::Parser;
+use anyhow::{anyhow, bail, Result};
+use async_compression::tokio::{bufread::BrotliDecoder, write::BrotliEncoder};
+use clap::{Parser, ValueEnum};
 use regex::Regex;
-use reqwest::{Client, Url};
+use reqwest::{Client, Response, Url};
 use scraper::{Html, Selector};
+use serde_json::json;
 use tokio::{
     fs::File,
-    io::{AsyncWriteExt, BufWriter},
-    join, spawn,
-    sync::broadcast::{self, error::RecvError, Sender},
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    spawn,
+    sync::{mpsc, Mutex},
+    time::sleep,
 };
-use tokio_tar::{Builder, Header};
-use tracing::info;
+use tokio_stream::StreamExt;
+use tokio_tar::{Archive, Builder, Header};
+use tracing::{info, warn};
 use tracing_subscriber::fmt;
 
+/// The ceiling applied to the exponential backoff delay between retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+mod epub;
+mod site;
+
+use site::SiteProfile;
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// A Brotli-compressed tarball of one `.txt` file per chapter.
+    Tarball,
+    /// A valid EPUB3 book with a spine, table of contents and metadata.
+    Epub,
+}
+
 #[derive(Parser)]
 struct Args {
     #[arg(short, long)]
@@ -28,65 +52,508 @@ struct Args {
     )]
     /// The user agent to use when making requests to RoyalRoad
     user_agent: String,
+    #[arg(short, long, value_enum, default_value_t = Format::Tarball)]
+    /// The output format to emit for each fiction.
+    format: Format,
+    #[arg(short, long, default_value_t = 5)]
+    /// The number of chapters to fetch concurrently per fiction.
+    concurrency: usize,
+    #[arg(short, long, default_value_t = 3)]
+    /// The number of times to retry a transient HTTP failure before giving up.
+    max_retries: usize,
+    #[arg(short, long, default_value_t = 0)]
+    /// Milliseconds to sleep before each request, per worker, to be polite.
+    delay_ms: u64,
+    #[arg(long)]
+    /// Update an existing archive in place, fetching only chapters not yet present.
+    update: bool,
+}
+
+/// How aggressively to retry transient failures and how politely to pace
+/// requests; shared by every HTTP fetch in the program.
+#[derive(Copy, Clone)]
+struct RetryConfig {
+    max_retries: usize,
+    delay: Duration,
+}
+
+impl RetryConfig {
+    fn new(max_retries: usize, delay_ms: u64) -> Self {
+        Self {
+            max_retries,
+            delay: Duration::from_millis(delay_ms),
+        }
+    }
+}
+
+/// Whether a failed status code is worth retrying: rate-limiting (429) and
+/// transient server errors (5xx), but not client errors like 404.
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Whether a transport-level error is transient enough to be worth retrying.
+fn is_retryable_error(error: &reqwest::Error) -> bool {
+    error.is_timeout() || error.is_connect() || error.is_request()
+}
+
+/// The backoff delay for a given attempt: 1s, 2s, 4s, … capped at [`MAX_BACKOFF`].
+fn backoff(attempt: usize) -> Duration {
+    let seconds = 1u64.checked_shl(attempt as u32).unwrap_or(u64::MAX);
+    Duration::from_secs(seconds).min(MAX_BACKOFF)
+}
+
+/// Parse a `Retry-After` header in either of the forms RFC 7231 permits: a
+/// delay in whole seconds, or an HTTP-date to wait until.
+///
+/// A date in the past (or one we can't parse) yields `None` so the caller falls
+/// back to the computed exponential backoff.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let when = httpdate::parse_http_date(value).ok()?;
+    when.duration_since(SystemTime::now()).ok()
+}
+
+/// Fetch a URL, retrying transient failures with exponential backoff.
+///
+/// A `Retry-After` header takes precedence over the computed backoff when the
+/// server supplies one. The configured per-request delay is applied before each
+/// attempt so the pacing survives retries as well as first tries.
+async fn fetch(client: &Client, url: &Url, retry: RetryConfig) -> Result<Response> {
+    let mut attempt = 0;
+    loop {
+        if !retry.delay.is_zero() {
+            sleep(retry.delay).await;
+        }
+
+        match client.get(url.clone()).send().await {
+            Ok(response) => {
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(response);
+                }
+                if is_retryable_status(status) && attempt < retry.max_retries {
+                    let wait = retry_after(&response).unwrap_or_else(|| backoff(attempt));
+                    warn!("{url} returned {status}, retrying in {wait:?}");
+                    sleep(wait).await;
+                    attempt += 1;
+                    continue;
+                }
+                return Err(anyhow!("request to {url} failed with status {status}"));
+            }
+            Err(error) => {
+                if is_retryable_error(&error) && attempt < retry.max_retries {
+                    let wait = backoff(attempt);
+                    warn!("request to {url} failed ({error}), retrying in {wait:?}");
+                    sleep(wait).await;
+                    attempt += 1;
+                    continue;
+                }
+                return Err(error.into());
+            }
+        }
+    }
+}
+
+/// The selectors used to locate content on RoyalRoad's pages, bundled so they
+/// can be shared across the scraping passes without re-parsing them per page.
+struct Selectors {
+    chapter_title: Selector,
+    fiction_title: Selector,
+    fiction_author: Selector,
+    fiction_description: Selector,
+    fiction_cover: Selector,
+    chapter_rows: Selector,
+    chapter_row_link: Selector,
+}
+
+impl Selectors {
+    fn new() -> Self {
+        Self {
+            chapter_title: Selector::parse("h1").unwrap(),
+            fiction_title: Selector::parse("div.fic-title h1").unwrap(),
+            fiction_author: Selector::parse("div.fic-title h4 a").unwrap(),
+            fiction_description: Selector::parse("div.description").unwrap(),
+            fiction_cover: Selector::parse("div.cover-art-container img").unwrap(),
+            chapter_rows: Selector::parse("table#chapters tbody tr.chapter-row").unwrap(),
+            chapter_row_link: Selector::parse("td a").unwrap(),
+        }
+    }
+}
+
+/// A single scraped chapter, kept in reading order so the output containers can
+/// be assembled with a correct spine and table of contents.
+pub struct Chapter {
+    /// The last path segment of the chapter URL, used to name archive entries.
+    /// Chapters recovered from an existing EPUB carry their heading here instead.
+    name: String,
+    /// The human-readable chapter heading scraped from the page.
+    title: String,
+    /// The chapter body, one entry per extracted `<p>` element.
+    paragraphs: Vec<String>,
+}
+
+/// Book-level metadata scraped once from a fiction's overview page.
+pub struct FictionMeta {
+    /// A stable identifier for the book (the overview URL, or the guessed base
+    /// name for hosts without an overview page).
+    identifier: String,
+    title: String,
+    author: String,
+    description: String,
+    /// The absolute URL of the cover image, when one is present.
+    cover_url: Option<String>,
+    /// The chapter titles in reading order, as listed on the overview page (or,
+    /// for hosts without a chapter index, as scraped from each chapter page).
+    chapters: Vec<String>,
+}
+
+impl FictionMeta {
+    /// A minimal metadata record for hosts we can't scrape an overview from,
+    /// deriving a human-readable title from the guessed base name.
+    fn fallback(base_name: &str) -> Self {
+        Self {
+            identifier: base_name.to_owned(),
+            title: base_name.replace('-', " "),
+            author: String::new(),
+            description: String::new(),
+            cover_url: None,
+            chapters: Vec::new(),
+        }
+    }
+}
+
+/// Guess a filesystem-friendly base name for the output file, preferring the
+/// RoyalRoad fiction slug and falling back to the URL's last path segment.
+fn guess_base_name(name_regex: &Regex, url: &Url) -> String {
+    if let Some(slug) = name_regex
+        .captures(url.path())
+        .and_then(|captures| captures.get(1))
+    {
+        return slug.as_str().to_owned();
+    }
+    url.path_segments()
+        .and_then(|segments| segments.filter(|s| !s.is_empty()).last())
+        .map(|segment| segment.to_owned())
+        .or_else(|| url.host_str().map(|host| host.to_owned()))
+        .unwrap_or_else(|| "fiction".to_owned())
+}
+
+/// Derive a fiction's overview URL from one of its chapter URLs.
+fn overview_url(chapter_url: &Url) -> Result<Url> {
+    let overview = Regex::new(r"/fiction/\d+/[\w-]+")?
+        .find(chapter_url.path())
+        .map(|m| m.as_str().to_owned());
+    match overview {
+        Some(path) => Ok(chapter_url.join(&path)?),
+        None => bail!("could not derive fiction overview url from chapter url"),
+    }
 }
 
-async fn parse_chapters(
-    paragraph_selector: &Selector,
-    chapter_button_selector: &Selector,
+/// Scrape the overview page once, collecting book-level metadata and the full
+/// ordered list of chapter URLs exposed by the table-of-contents table.
+///
+/// Reading the chapter list up front lets us fan the fetches out across a
+/// worker pool instead of following the "next chapter" link one page at a time.
+async fn scrape_overview(
+    selectors: &Selectors,
     client: &Client,
-    url: Url,
-    archive: Sender<(String, Vec<u8>)>,
-) -> Result<()> {
-    let chapter_name = match url.path_segments().and_then(|segments| segments.last()) {
-        Some(chapter_name) => chapter_name.to_owned(),
+    chapter_url: &Url,
+    retry: RetryConfig,
+) -> Result<(FictionMeta, Vec<Url>)> {
+    let overview = overview_url(chapter_url)?;
+    let document = Html::parse_document(&fetch(client, &overview, retry).await?.text().await?);
+
+    let text = |selector: &Selector| {
+        document
+            .select(selector)
+            .next()
+            .map(|ele| ele.text().collect::<String>().trim().to_owned())
+            .unwrap_or_default()
+    };
+
+    let cover_url = document
+        .select(&selectors.fiction_cover)
+        .next()
+        .and_then(|ele| ele.attr("src"))
+        .and_then(|src| overview.join(src).ok())
+        .map(|url| url.to_string());
+
+    let mut chapter_urls = Vec::new();
+    let mut chapter_titles = Vec::new();
+    for row in document.select(&selectors.chapter_rows) {
+        let Some(href) = row.attr("data-url") else {
+            continue;
+        };
+        chapter_urls.push(overview.join(href)?);
+        chapter_titles.push(
+            row.select(&selectors.chapter_row_link)
+                .next()
+                .map(|link| link.text().collect::<String>().trim().to_owned())
+                .unwrap_or_default(),
+        );
+    }
+    if chapter_urls.is_empty() {
+        bail!("no chapters found on fiction overview page");
+    }
+
+    let meta = FictionMeta {
+        identifier: overview.to_string(),
+        title: text(&selectors.fiction_title),
+        author: text(&selectors.fiction_author),
+        description: text(&selectors.fiction_description),
+        cover_url,
+        chapters: chapter_titles,
+    };
+
+    Ok((meta, chapter_urls))
+}
+
+/// Extract the paragraphs and next-chapter link from an already-parsed page,
+/// delegating the site-specific selection to `profile`.
+fn extract_chapter(
+    selectors: &Selectors,
+    profile: &dyn SiteProfile,
+    url: &Url,
+    document: &Html,
+) -> Result<(Chapter, Option<Url>)> {
+    let name = match url.path_segments().and_then(|segments| segments.last()) {
+        Some(name) => name.to_owned(),
         None => bail!("chapter does not have name"),
     };
 
-    let document = Html::parse_document(&client.get(url.clone()).send().await?.text().await?);
-    let chapter_contents = document
-        .select(paragraph_selector)
-        .map(|ele| {
-            ele.text()
-                .map(|t| t.to_string())
-                .collect::<Vec<_>>()
-                .join("")
-        })
-        .collect::<Vec<_>>()
-        .join("\n\n");
-    info!("parsed chapter {chapter_name}");
-
-    let next_button_link = document
-        .select(chapter_button_selector)
-        .find(|button| {
-            let original_text = button.text().collect::<String>().to_lowercase();
-            let cleaned_text = original_text.trim();
-
-            if cleaned_text == "next chapter" {
-                true
-            } else {
-                false
+    let paragraphs = profile.content_paragraphs(document);
+
+    let title = document
+        .select(&selectors.chapter_title)
+        .next()
+        .map(|ele| ele.text().collect::<String>().trim().to_owned())
+        .filter(|title| !title.is_empty())
+        .unwrap_or_else(|| name.clone());
+
+    let next = profile
+        .next_link(document)
+        .map(|href| url.join(&href))
+        .transpose()?;
+
+    Ok((
+        Chapter {
+            name,
+            title,
+            paragraphs,
+        },
+        next,
+    ))
+}
+
+/// Fetch every chapter in `chapter_urls` across a pool of `concurrency` worker
+/// tasks, preserving the original reading order in the returned vector.
+///
+/// Workers pull from a shared work queue and report each parsed chapter back
+/// over an mpsc channel tagged with its index, so ordering survives the
+/// out-of-order completion inherent to concurrent fetching.
+async fn fetch_chapters(
+    selectors: &Arc<Selectors>,
+    profile: &Arc<dyn SiteProfile>,
+    client: &Client,
+    chapter_urls: Vec<Url>,
+    concurrency: usize,
+    retry: RetryConfig,
+) -> Result<Vec<Chapter>> {
+    let total = chapter_urls.len();
+    let queue = Arc::new(Mutex::new(
+        chapter_urls.into_iter().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let (sender, mut receiver) = mpsc::unbounded_channel::<(usize, Chapter)>();
+
+    let mut workers = Vec::with_capacity(concurrency.max(1));
+    for _ in 0..concurrency.max(1) {
+        let queue = queue.clone();
+        let selectors = selectors.clone();
+        let profile = profile.clone();
+        let client = client.clone();
+        let sender = sender.clone();
+        workers.push(spawn(async move {
+            loop {
+                let (index, url) = match queue.lock().await.pop_front() {
+                    Some(job) => job,
+                    None => break,
+                };
+                let document =
+                    Html::parse_document(&fetch(&client, &url, retry).await?.text().await?);
+                let (chapter, _) = extract_chapter(&selectors, profile.as_ref(), &url, &document)?;
+                info!("parsed chapter {}", chapter.name);
+                let _ = sender.send((index, chapter));
             }
-        })
-        .and_then(|button| button.attr("href"));
-
-    if let Some(next_chapter) = next_button_link {
-        let sender_clone = archive.clone();
-        spawn(async move {
-            sender_clone
-                .send((chapter_name + ".txt", chapter_contents.into_bytes()))
-                .unwrap();
-        });
-        Box::pin(parse_chapters(
-            paragraph_selector,
-            chapter_button_selector,
-            client,
-            url.join(next_chapter)?,
-            archive.clone(),
-        ))
-        .await
-    } else {
-        Ok(())
+            Ok::<_, anyhow::Error>(())
+        }));
+    }
+    drop(sender);
+
+    let mut slots = (0..total).map(|_| None).collect::<Vec<Option<Chapter>>>();
+    while let Some((index, chapter)) = receiver.recv().await {
+        slots[index] = Some(chapter);
+    }
+    for worker in workers {
+        worker.await??;
+    }
+
+    Ok(slots.into_iter().flatten().collect())
+}
+
+/// Collect chapters by following the profile's "next chapter" link one page at
+/// a time, starting from `url`. Used for hosts that expose no chapter index.
+async fn chain_chapters(
+    selectors: &Selectors,
+    profile: &dyn SiteProfile,
+    client: &Client,
+    url: Url,
+    retry: RetryConfig,
+) -> Result<Vec<Chapter>> {
+    let mut chapters = Vec::new();
+    let mut next = Some(url);
+
+    while let Some(url) = next.take() {
+        let document = Html::parse_document(&fetch(client, &url, retry).await?.text().await?);
+        let (chapter, following) = extract_chapter(selectors, profile, &url, &document)?;
+        info!("parsed chapter {}", chapter.name);
+        chapters.push(chapter);
+        next = following;
+    }
+
+    Ok(chapters)
+}
+
+/// Serialize the book-level metadata as the `metadata.json` catalog entry.
+fn metadata_json(meta: &FictionMeta) -> Result<Vec<u8>> {
+    let value = json!({
+        "identifier": meta.identifier,
+        "title": meta.title,
+        "author": meta.author,
+        "description": meta.description,
+        "cover_url": meta.cover_url,
+        "chapters": meta.chapters,
+    });
+    Ok(serde_json::to_vec_pretty(&value)?)
+}
+
+/// Append a single byte blob to the tarball under `name`.
+async fn append_entry<W>(builder: &mut Builder<W>, name: &str, bytes: &[u8]) -> Result<()>
+where
+    W: AsyncWriteExt + Unpin + Send,
+{
+    let mut header = Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o777);
+    builder.append_data(&mut header, name, bytes).await?;
+    Ok(())
+}
+
+/// Write the collected chapters as a Brotli-compressed tarball of `.txt` files.
+///
+/// A `metadata.json` catalog is written first, and each chapter is prefixed with
+/// a zero-padded index so the entries sort into reading order.
+async fn write_tarball(base_name: &str, meta: &FictionMeta, chapters: &[Chapter]) -> Result<()> {
+    let mut file = File::create(format!("{base_name}.tar.br")).await?;
+    let mut buf_writer = BufWriter::new(&mut file);
+    let mut archive_builder = Builder::new_non_terminated(BrotliEncoder::new(&mut buf_writer));
+
+    append_entry(&mut archive_builder, "metadata.json", &metadata_json(meta)?).await?;
+
+    for (index, chapter) in chapters.iter().enumerate() {
+        let bytes = chapter.paragraphs.join("\n\n").into_bytes();
+        let name = format!("{:04}-{}.txt", index + 1, chapter.name);
+        append_entry(&mut archive_builder, &name, &bytes).await?;
+        info!("added chapter {} to archive", chapter.name);
+    }
+
+    archive_builder.into_inner().await?.shutdown().await?;
+    buf_writer.flush().await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Recover the chapters already stored in a Brotli-compressed tarball, in the
+/// reading order encoded by their zero-padded name prefixes.
+///
+/// The `metadata.json` catalog is ignored; only the `.txt` chapter bodies are
+/// reconstructed so `--update` can rebuild the archive around them.
+async fn read_existing_tarball(base_name: &str) -> Result<Vec<Chapter>> {
+    let file = match File::open(format!("{base_name}.tar.br")).await {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut archive = Archive::new(BrotliDecoder::new(BufReader::new(file)));
+    let mut entries = archive.entries()?;
+
+    let mut chapters = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let Some(name) = chapter_entry_name(&path) else {
+            continue;
+        };
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).await?;
+        let paragraphs = contents.split("\n\n").map(|p| p.to_owned()).collect();
+        chapters.push((path, Chapter { name: name.clone(), title: name, paragraphs }));
     }
+
+    chapters.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(chapters.into_iter().map(|(_, chapter)| chapter).collect())
+}
+
+/// Recover a chapter's name from a tarball entry path, dropping the zero-padded
+/// `NNNN-` index prefix. Returns `None` for non-chapter entries like the
+/// `metadata.json` catalog.
+fn chapter_entry_name(path: &str) -> Option<String> {
+    let stem = path.strip_suffix(".txt")?;
+    Some(stem.split_once('-').map_or(stem, |(_, rest)| rest).to_owned())
+}
+
+/// Recover the chapters already stored in an existing EPUB book. A missing file
+/// is treated as an empty archive so the first `--update` run scrapes in full.
+async fn read_existing_epub(base_name: &str) -> Result<Vec<Chapter>> {
+    let bytes = match tokio::fs::read(format!("{base_name}.epub")).await {
+        Ok(bytes) => bytes,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error.into()),
+    };
+    epub::read(&bytes)
+}
+
+/// Assemble and write the collected chapters as an EPUB3 book.
+async fn write_epub(
+    client: &Client,
+    base_name: &str,
+    meta: &FictionMeta,
+    chapters: &[Chapter],
+    retry: RetryConfig,
+) -> Result<()> {
+    let cover = match &meta.cover_url {
+        Some(url) => Some(fetch(client, &Url::parse(url)?, retry).await?.bytes().await?.to_vec()),
+        None => None,
+    };
+
+    let bytes = epub::build(meta, chapters, cover.as_deref())?;
+    let mut file = File::create(format!("{base_name}.epub")).await?;
+    file.write_all(&bytes).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    info!("wrote epub for {base_name}");
+    Ok(())
 }
 
 #[tokio::main]
@@ -94,65 +561,86 @@ async fn main() -> Result<()> {
     fmt().init();
     let args = Args::parse();
     let client = Client::builder().user_agent(args.user_agent).build()?;
-    let paragraph_selector =
-        Arc::new(Selector::parse("div.chapter-inner.chapter-content > p").unwrap());
-    let chapter_button_selector = Arc::new(Selector::parse("a.btn.btn-primary.col-xs-12").unwrap());
+    let selectors = Arc::new(Selectors::new());
+    let retry = RetryConfig::new(args.max_retries, args.delay_ms);
 
-    for initial_chapter in args.initial_chapter {
-        let (sender, mut receiver) = broadcast::channel(10000);
-        let base_name = match Regex::new(r"/fiction/\d+/([\w-]+)")?
-            .captures(&initial_chapter)
-            .and_then(|captures| captures.get(1))
-            .map(|group| group.as_str())
-        {
-            Some(base_name) => base_name.to_owned(),
-            None => bail!("could not guess fiction name from first chapter url"),
-        };
+    let name_regex = Regex::new(r"/fiction/\d+/([\w-]+)")?;
 
+    for initial_chapter in args.initial_chapter {
         let url = Url::parse(&initial_chapter)?;
+        let base_name = guess_base_name(&name_regex, &url);
+        let profile = site::for_url(&url);
 
-        let (parse_result, death_result) = join!(
-            parse_chapters(
-                &paragraph_selector,
-                &chapter_button_selector,
-                &client,
-                url,
-                sender
-            ),
-            async move {
-                let mut file = File::create(format!("{base_name}.tar.br")).await?;
-                let mut buf_writer = BufWriter::new(&mut file);
-                let mut archive_builder =
-                    Builder::new_non_terminated(BrotliEncoder::new(&mut buf_writer));
-
-                loop {
-                    let (name, bytes): (String, Vec<u8>) = match receiver.recv().await {
-                        Ok(tuple) => tuple,
-                        Err(e) => match e {
-                            RecvError::Lagged(_) => continue,
-                            _ => break,
-                        },
-                    };
-
-                    let mut header = Header::new_gnu();
-                    header.set_size(bytes.len() as u64);
-                    header.set_mode(0o777);
-                    archive_builder
-                        .append_data(&mut header, &name, bytes.as_slice())
-                        .await?;
-                    info!("added chapter {name} to archive")
-                }
-
-                archive_builder.into_inner().await?.shutdown().await?;
-                buf_writer.flush().await?;
-                file.flush().await?;
-                file.sync_all().await?;
-                Ok::<_, anyhow::Error>(())
+        let (meta, chapters) = if args.update {
+            if !site::has_chapter_index(&url) {
+                bail!("--update requires a site that exposes a chapter index");
             }
-        );
+            let (meta, chapter_urls) = scrape_overview(&selectors, &client, &url, retry).await?;
+            let existing = match args.format {
+                Format::Tarball => read_existing_tarball(&base_name).await?,
+                Format::Epub => read_existing_epub(&base_name).await?,
+            };
+            let already = existing.len().min(chapter_urls.len());
+            info!(
+                "{base_name}: {already} chapters already archived, {} new",
+                chapter_urls.len() - already
+            );
+            let new_urls = chapter_urls[already..].to_vec();
+            let fetched =
+                fetch_chapters(&selectors, &profile, &client, new_urls, args.concurrency, retry)
+                    .await?;
+            let mut chapters = existing;
+            chapters.extend(fetched);
+            (meta, chapters)
+        } else if site::has_chapter_index(&url) {
+            let (meta, chapter_urls) = scrape_overview(&selectors, &client, &url, retry).await?;
+            let chapters =
+                fetch_chapters(&selectors, &profile, &client, chapter_urls, args.concurrency, retry)
+                    .await?;
+            (meta, chapters)
+        } else {
+            let chapters = chain_chapters(&selectors, profile.as_ref(), &client, url, retry).await?;
+            let mut meta = FictionMeta::fallback(&base_name);
+            meta.chapters = chapters.iter().map(|chapter| chapter.title.clone()).collect();
+            (meta, chapters)
+        };
 
-        parse_result?;
-        death_result?;
+        match args.format {
+            Format::Tarball => write_tarball(&base_name, &meta, &chapters).await?,
+            Format::Epub => write_epub(&client, &base_name, &meta, &chapters, retry).await?,
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        // A wildly large attempt must saturate rather than overflow the shift.
+        assert_eq!(backoff(1000), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn only_rate_limit_and_server_errors_are_retryable() {
+        use reqwest::StatusCode;
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable_status(StatusCode::NOT_FOUND));
+        assert!(!is_retryable_status(StatusCode::OK));
+    }
+
+    #[test]
+    fn chapter_entry_name_strips_index_prefix() {
+        assert_eq!(chapter_entry_name("0001-a-long-title.txt").as_deref(), Some("a-long-title"));
+        assert_eq!(chapter_entry_name("0042-prologue.txt").as_deref(), Some("prologue"));
+        // Non-chapter entries are ignored.
+        assert_eq!(chapter_entry_name("metadata.json"), None);
+    }
+}
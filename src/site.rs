@@ -0,0 +1,198 @@
+use std::{cmp::Ordering, sync::Arc};
+
+use regex::Regex;
+use reqwest::Url;
+use scraper::{ElementRef, Html, Selector};
+
+/// A per-host strategy for pulling chapter text and the link to the following
+/// chapter out of a parsed page. Selecting the right profile by host lets the
+/// scraper target arbitrary serial-fiction sites without code changes.
+pub trait SiteProfile: Send + Sync {
+    /// The chapter body, one entry per extracted paragraph.
+    fn content_paragraphs(&self, document: &Html) -> Vec<String>;
+    /// The `href` of the "next chapter" link, when the page exposes one.
+    fn next_link(&self, document: &Html) -> Option<String>;
+}
+
+/// Pick the profile for a URL's host, falling back to the generic readability
+/// extractor for hosts we have no bespoke profile for.
+pub fn for_url(url: &Url) -> Arc<dyn SiteProfile> {
+    match url.host_str() {
+        Some(host) if host.ends_with("royalroad.com") => Arc::new(RoyalRoad::new()),
+        _ => Arc::new(Generic::new()),
+    }
+}
+
+/// Whether a host exposes a full chapter index on its overview page, allowing
+/// the fast concurrent-fetch path instead of chaining "next" links.
+pub fn has_chapter_index(url: &Url) -> bool {
+    matches!(url.host_str(), Some(host) if host.ends_with("royalroad.com"))
+}
+
+/// The hand-tuned profile for RoyalRoad's current DOM.
+struct RoyalRoad {
+    paragraph: Selector,
+    chapter_button: Selector,
+}
+
+impl RoyalRoad {
+    fn new() -> Self {
+        Self {
+            paragraph: Selector::parse("div.chapter-inner.chapter-content > p").unwrap(),
+            chapter_button: Selector::parse("a.btn.btn-primary.col-xs-12").unwrap(),
+        }
+    }
+}
+
+impl SiteProfile for RoyalRoad {
+    fn content_paragraphs(&self, document: &Html) -> Vec<String> {
+        document
+            .select(&self.paragraph)
+            .map(|ele| ele.text().map(|t| t.to_string()).collect::<String>())
+            .collect()
+    }
+
+    fn next_link(&self, document: &Html) -> Option<String> {
+        document
+            .select(&self.chapter_button)
+            .find(|button| {
+                button
+                    .text()
+                    .collect::<String>()
+                    .trim()
+                    .eq_ignore_ascii_case("next chapter")
+            })
+            .and_then(|button| button.attr("href"))
+            .map(|href| href.to_owned())
+    }
+}
+
+/// A site-agnostic fallback that scores candidate block elements by text
+/// density and extracts the paragraphs of the highest-scoring node.
+struct Generic {
+    candidates: Selector,
+    paragraph: Selector,
+    anchor: Selector,
+    positive: Regex,
+    negative: Regex,
+}
+
+impl Generic {
+    fn new() -> Self {
+        Self {
+            candidates: Selector::parse("div, article, section, main, td").unwrap(),
+            paragraph: Selector::parse("p").unwrap(),
+            anchor: Selector::parse("a").unwrap(),
+            positive: Regex::new(r"(?i)article|content|chapter|post").unwrap(),
+            negative: Regex::new(r"(?i)comment|sidebar|nav|footer").unwrap(),
+        }
+    }
+
+    /// Score a candidate by the density of its link-free text, boosting
+    /// containers whose class/id look like body content and penalizing chrome.
+    fn score(&self, element: &ElementRef) -> f64 {
+        let text_len: usize = element.text().map(|t| t.len()).sum();
+        let link_len: usize = element
+            .select(&self.anchor)
+            .flat_map(|a| a.text())
+            .map(|t| t.len())
+            .sum();
+        let tags = element
+            .descendants()
+            .filter(|node| node.value().is_element())
+            .count()
+            .max(1);
+
+        let density = text_len.saturating_sub(link_len) as f64 / tags as f64;
+
+        let value = element.value();
+        let attrs = format!(
+            "{} {}",
+            value.id().unwrap_or_default(),
+            value.attr("class").unwrap_or_default()
+        );
+        let mut score = density;
+        if self.positive.is_match(&attrs) {
+            score *= 1.5;
+        }
+        if self.negative.is_match(&attrs) {
+            score *= 0.2;
+        }
+        score
+    }
+}
+
+impl SiteProfile for Generic {
+    fn content_paragraphs(&self, document: &Html) -> Vec<String> {
+        let best = document.select(&self.candidates).max_by(|a, b| {
+            self.score(a)
+                .partial_cmp(&self.score(b))
+                .unwrap_or(Ordering::Equal)
+        });
+
+        let Some(best) = best else {
+            return Vec::new();
+        };
+
+        let paragraphs = best
+            .select(&self.paragraph)
+            .map(|p| p.text().collect::<String>().trim().to_owned())
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>();
+        if !paragraphs.is_empty() {
+            return paragraphs;
+        }
+
+        // No explicit `<p>` markup: fall back to the node's own text lines.
+        best.text()
+            .map(|line| line.trim().to_owned())
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    fn next_link(&self, document: &Html) -> Option<String> {
+        document
+            .select(&self.anchor)
+            .find(|a| a.text().collect::<String>().to_lowercase().contains("next"))
+            .and_then(|a| a.attr("href"))
+            .map(|href| href.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generic_extracts_the_densest_content_block() {
+        let html = r#"
+            <html><body>
+              <div class="sidebar"><a href="#">home</a><a href="#">about</a></div>
+              <div class="chapter-content">
+                <p>The first paragraph of the story is reasonably long.</p>
+                <p>The second paragraph carries the plot further along.</p>
+              </div>
+              <div class="comments"><p>nice chapter!</p></div>
+            </body></html>
+        "#;
+        let document = Html::parse_document(html);
+        let paragraphs = Generic::new().content_paragraphs(&document);
+        assert_eq!(
+            paragraphs,
+            vec![
+                "The first paragraph of the story is reasonably long.".to_owned(),
+                "The second paragraph carries the plot further along.".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn generic_finds_the_next_link() {
+        let html = r#"<html><body>
+            <a href="/prev">Previous</a>
+            <a href="/next-chapter">Next Chapter</a>
+        </body></html>"#;
+        let document = Html::parse_document(html);
+        assert_eq!(Generic::new().next_link(&document).as_deref(), Some("/next-chapter"));
+    }
+}